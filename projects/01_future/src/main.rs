@@ -1,45 +1,225 @@
 use std::{
+    cell::UnsafeCell,
     future::Future,
     pin::Pin,
-    task::{Context, Poll},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
     time::Duration,
 };
 
-pin_project_lite::pin_project! {
-    struct Select<F1, F2> {
-        #[pin]
-        left: F1,
-        #[pin]
-        right: F2,
-    }
+use slab::Slab;
+
+/// A heap-allocated, dynamically-dispatched future, boxed up so a collection
+/// can hold a mix of concrete future types.
+type BoxFuture<O> = Pin<Box<dyn Future<Output = O>>>;
+
+/// Polls a set of futures together, returning the output of whichever
+/// completes first along with its index and the rest, untouched, so the
+/// caller can keep waiting on them.
+///
+/// A rotating start offset is used so that no single future is favoured: the
+/// one after whichever index we started at last time gets polled first.
+pub struct SelectAll<O> {
+    futures: Vec<BoxFuture<O>>,
+    offset: usize,
 }
 
-impl<F1: Future, F2: Future> Future for Select<F1, F2> {
-    type Output = Either<F1::Output, F2::Output>;
+impl<O> Future for SelectAll<O> {
+    type Output = (O, usize, Vec<BoxFuture<O>>);
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
+        let this = self.get_mut();
+        let n = this.futures.len();
+        let start = this.offset % n;
+        this.offset = this.offset.wrapping_add(1);
 
-        if let Poll::Ready(left) = this.left.poll(cx) {
-            return Poll::Ready(Either::Left(left));
-        }
-
-        if let Poll::Ready(right) = this.right.poll(cx) {
-            return Poll::Ready(Either::Right(right));
+        for i in 0..n {
+            let idx = (start + i) % n;
+            if let Poll::Ready(output) = this.futures[idx].as_mut().poll(cx) {
+                let mut remaining = std::mem::take(&mut this.futures);
+                drop(remaining.remove(idx));
+                return Poll::Ready((output, idx, remaining));
+            }
         }
 
         Poll::Pending
     }
 }
 
+/// Race a list of futures, returning the output of the first to complete,
+/// its index in `futures`, and the other futures for the caller to reuse.
+pub fn select_all<O>(futures: Vec<BoxFuture<O>>) -> SelectAll<O> {
+    assert!(!futures.is_empty(), "select_all requires at least one future");
+    SelectAll { futures, offset: 0 }
+}
+
 #[derive(Debug)]
 enum Either<L, R> {
     Left(L),
     Right(R),
 }
 
-async fn select<A: Future, B: Future>(left: A, right: B) -> Either<A::Output, B::Output> {
-    Select { left, right }.await
+/// Race exactly two futures, implemented as a thin wrapper over `select_all`.
+async fn select<A, B>(left: A, right: B) -> Either<A::Output, B::Output>
+where
+    A: Future + 'static,
+    B: Future + 'static,
+{
+    let left: BoxFuture<Either<A::Output, B::Output>> = Box::pin(async move { Either::Left(left.await) });
+    let right: BoxFuture<Either<A::Output, B::Output>> = Box::pin(async move { Either::Right(right.await) });
+
+    let (output, _idx, _rest) = select_all(vec![left, right]).await;
+    output
+}
+
+const IDLE: usize = 0;
+const POLLING: usize = 1;
+const COMPLETE: usize = 2;
+
+enum FutureOrOutput<Fut: Future> {
+    Future(Fut),
+    Output(Fut::Output),
+}
+
+struct Inner<Fut: Future> {
+    state: AtomicUsize,
+    // guarded by `state`: only the task that wins the IDLE -> POLLING CAS may
+    // touch the `Future` variant, and it's never mutated again once COMPLETE.
+    fut_or_output: UnsafeCell<FutureOrOutput<Fut>>,
+    wakers: Mutex<Slab<Option<Waker>>>,
+}
+
+unsafe impl<Fut: Future + Send> Send for Inner<Fut> where Fut::Output: Send {}
+unsafe impl<Fut: Future + Send> Sync for Inner<Fut> where Fut::Output: Send {}
+
+/// A future that can be cloned and awaited from multiple places at once; the
+/// wrapped future is polled by at most one clone at a time, runs to
+/// completion only once, and every clone observes the same output.
+pub struct Shared<Fut: Future> {
+    inner: Arc<Inner<Fut>>,
+    waker_key: Option<usize>,
+}
+
+pub fn shared<Fut: Future>(fut: Fut) -> Shared<Fut> {
+    Shared {
+        inner: Arc::new(Inner {
+            state: AtomicUsize::new(IDLE),
+            fut_or_output: UnsafeCell::new(FutureOrOutput::Future(fut)),
+            wakers: Mutex::new(Slab::new()),
+        }),
+        waker_key: None,
+    }
+}
+
+impl<Fut: Future> Shared<Fut> {
+    fn register(&mut self, cx: &Context<'_>) {
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        match self.waker_key {
+            Some(key) => wakers[key] = Some(cx.waker().clone()),
+            None => self.waker_key = Some(wakers.insert(Some(cx.waker().clone()))),
+        }
+    }
+
+    fn deregister(&mut self) {
+        if let Some(key) = self.waker_key.take() {
+            self.inner.wakers.lock().unwrap().remove(key);
+        }
+    }
+}
+
+impl<Fut: Future> Clone for Shared<Fut> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            waker_key: None,
+        }
+    }
+}
+
+impl<Fut: Future> Drop for Shared<Fut> {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+impl<Fut: Future> Future for Shared<Fut>
+where
+    Fut::Output: Clone,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if this.inner.state.load(Ordering::Acquire) == IDLE
+            && this
+                .inner
+                .state
+                .compare_exchange(IDLE, POLLING, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            // we won the race to drive the inner future.
+            // SAFETY: POLLING is held exclusively by us until we store a new state below.
+            let fut = unsafe { &mut *this.inner.fut_or_output.get() };
+            let FutureOrOutput::Future(fut) = fut else {
+                unreachable!("still POLLING implies the future hasn't completed yet")
+            };
+            // SAFETY: `fut` came from a `Fut` that was never moved out of its `Arc`.
+            let fut = unsafe { Pin::new_unchecked(fut) };
+
+            match fut.poll(cx) {
+                Poll::Ready(output) => {
+                    // SAFETY: we still exclusively hold POLLING.
+                    unsafe {
+                        *this.inner.fut_or_output.get() = FutureOrOutput::Output(output.clone());
+                    }
+                    this.inner.state.store(COMPLETE, Ordering::Release);
+                    for (_, waker) in this.inner.wakers.lock().unwrap().iter_mut() {
+                        if let Some(waker) = waker.take() {
+                            waker.wake();
+                        }
+                    }
+                    this.deregister();
+                    return Poll::Ready(output);
+                }
+                Poll::Pending => {
+                    // register before releasing back to IDLE: otherwise another
+                    // clone could win the IDLE->POLLING race, finish the future,
+                    // and drain+wake the slab before we're in it, leaving us
+                    // parked forever.
+                    this.register(cx);
+                    this.inner.state.store(IDLE, Ordering::Release);
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        if this.inner.state.load(Ordering::Acquire) == COMPLETE {
+            // SAFETY: COMPLETE is terminal; the output is never mutated again.
+            let output = unsafe {
+                match &*this.inner.fut_or_output.get() {
+                    FutureOrOutput::Output(output) => output.clone(),
+                    FutureOrOutput::Future(_) => unreachable!("COMPLETE implies the output was stored"),
+                }
+            };
+            this.deregister();
+            return Poll::Ready(output);
+        }
+
+        // either someone else is POLLING right now, or we lost the IDLE race:
+        // register to be woken once the winner finishes.
+        this.register(cx);
+        // the winner may have completed and drained the slab between our
+        // COMPLETE check above and `register`: if so, wake ourselves to pick
+        // up the output instead of staying parked forever.
+        if this.inner.state.load(Ordering::Acquire) == COMPLETE {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
 }
 
 #[tokio::main]
@@ -57,4 +237,44 @@ async fn main() {
     let res = select(left, right).await;
 
     println!("raced: {:?}", res);
+
+    // select_all over more than two futures, to show the rotating offset
+    // keeps things fair instead of always favouring the first entry.
+    let timers: Vec<BoxFuture<u64>> = vec![
+        Box::pin(async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            0
+        }),
+        Box::pin(async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            1
+        }),
+        Box::pin(async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            2
+        }),
+    ];
+
+    let (winner, idx, _rest) = select_all(timers).await;
+    println!("timer {idx} won with value {winner}");
+
+    // a one-time computation broadcast to several tasks via `shared`: only
+    // the first task to poll it actually runs the sleep, the rest just clone
+    // its result once it lands.
+    let config = shared(async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        "loaded config"
+    });
+
+    let mut joins = Vec::new();
+    for i in 0..5 {
+        let config = config.clone();
+        joins.push(tokio::spawn(async move {
+            let value = config.await;
+            println!("task {i} observed: {value}");
+        }));
+    }
+    for join in joins {
+        join.await.unwrap();
+    }
 }
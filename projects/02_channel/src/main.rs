@@ -119,6 +119,165 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     (tx, rx)
 }
 
+struct BoundedChannel<T> {
+    data: VecDeque<T>,
+    /// how many items `data` may hold before `send` suspends.
+    capacity: usize,
+    /// the single consumer waker, if any
+    recv: Option<Waker>,
+    /// senders parked waiting for room, in the order they suspended, each
+    /// tagged with the ticket handed out when it queued.
+    send_queue: VecDeque<(u64, Waker)>,
+    /// the next ticket to hand out to a queueing sender.
+    next_ticket: u64,
+    /// is the receiver still there?
+    receiver: bool,
+    /// how many senders are still there?
+    senders: usize,
+}
+
+pub struct BoundedReceiver<T> {
+    channel: Arc<Mutex<BoundedChannel<T>>>,
+}
+
+impl<T> BoundedReceiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| {
+            let mut channel = self.channel.lock().unwrap();
+            if let Some(t) = channel.data.pop_front() {
+                // we just freed up a slot: wake the longest-waiting sender,
+                // but leave it at the front of the queue. it removes itself
+                // once it actually claims the slot, so a brand-new sender
+                // can't race in and take the slot out from under it first.
+                if let Some((_, waker)) = channel.send_queue.front() {
+                    waker.wake_by_ref();
+                }
+                return Poll::Ready(Some(t));
+            }
+
+            if channel.senders == 0 {
+                return Poll::Ready(None);
+            }
+
+            channel.recv = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+// Wake every parked sender so a dropped receiver doesn't leave them hanging;
+// they'll observe `receiver == false` and resolve to `Err` on their next poll.
+impl<T> Drop for BoundedReceiver<T> {
+    fn drop(&mut self) {
+        let mut channel = self.channel.lock().unwrap();
+        channel.receiver = false;
+        for (_, waker) in channel.send_queue.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+pub struct BoundedSender<T> {
+    channel: Arc<Mutex<BoundedChannel<T>>>,
+}
+
+impl<T: Send> BoundedSender<T> {
+    /// Send a message over the channel, suspending while the buffer is full.
+    ///
+    /// # Errors
+    ///
+    /// Errors if the channel is closed.
+    pub async fn send(&self, t: T) -> Result<(), T> {
+        let mut value = Some(t);
+        let mut ticket = None;
+
+        poll_fn(|cx| {
+            let mut channel = self.channel.lock().unwrap();
+
+            if !channel.receiver {
+                return Poll::Ready(Err(value.take().unwrap()));
+            }
+
+            // only the sender at the front of the queue (or one that never
+            // had to queue at all) may take a freed slot, so a brand-new
+            // sender can't jump ahead of one that's already parked and was
+            // just woken for this exact slot.
+            let at_front = match ticket {
+                Some(ticket) => channel.send_queue.front().is_some_and(|(head, _)| *head == ticket),
+                None => channel.send_queue.is_empty(),
+            };
+
+            if at_front && channel.data.len() < channel.capacity {
+                if ticket.is_some() {
+                    channel.send_queue.pop_front();
+                }
+                channel.data.push_back(value.take().unwrap());
+
+                if let Some(waker) = channel.recv.take() {
+                    waker.wake();
+                }
+
+                return Poll::Ready(Ok(()));
+            }
+
+            // buffer is full, or someone else is still ahead of us: queue
+            // (once) behind any other blocked senders.
+            if ticket.is_none() {
+                let next = channel.next_ticket;
+                channel.next_ticket += 1;
+                channel.send_queue.push_back((next, cx.waker().clone()));
+                ticket = Some(next);
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        let mut channel = self.channel.lock().unwrap();
+        channel.senders += 1;
+        Self {
+            channel: Arc::clone(&self.channel),
+        }
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        let mut channel = self.channel.lock().unwrap();
+        channel.senders -= 1;
+        if channel.senders == 0 {
+            if let Some(waker) = channel.recv.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Creates a bounded mpsc channel: `send` suspends once `capacity` messages
+/// are buffered, applying backpressure to producers until the consumer catches up.
+pub fn bounded<T>(capacity: usize) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    let channel = Arc::new(Mutex::new(BoundedChannel {
+        data: VecDeque::new(),
+        capacity,
+        recv: None,
+        send_queue: VecDeque::new(),
+        next_ticket: 0,
+        receiver: true,
+        senders: 1,
+    }));
+
+    let tx = BoundedSender {
+        channel: Arc::clone(&channel),
+    };
+    let rx = BoundedReceiver { channel };
+    (tx, rx)
+}
+
 #[tokio::main]
 async fn main() {
     let (tx, mut rx) = channel();
@@ -140,4 +299,18 @@ async fn main() {
         println!("Received msg {x:?} after {dur:?}", dur = now.elapsed());
     }
     println!("Shutting down after {dur:?}", dur = now.elapsed());
+
+    let (tx, mut rx) = bounded(2);
+    tokio::spawn(async move {
+        for i in 0..5 {
+            let now = Instant::now();
+            tx.send(i).await.expect("channel should be open");
+            println!("sent {i} after {dur:?}", dur = now.elapsed());
+        }
+    });
+
+    while let Some(x) = rx.recv().await {
+        println!("Received bounded msg {x:?}");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
 }
@@ -4,151 +4,1024 @@ use std::{
     future::Future,
     ops::{Deref, DerefMut},
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll, Waker},
     time::Duration,
 };
 
-struct AsyncMutex<T> {
-    // the state that manages tasks waiting to acquire the lock
-    queue: Mutex<Queue>,
-    // The data the mutex is protecting
+// top bit of `AsyncSemaphore::state`: set while anyone is queued, so the
+// fast path knows it isn't safe to take and must go through `queue` instead.
+// packed into the same atomic as the permit count so a failed fast-path
+// attempt can mark contention in the very same CAS that observed the
+// shortfall, with no gap a concurrent fast path could slip through.
+const CONTENDED: u64 = 1 << 63;
+
+struct SemQueue {
+    // The current queue tail
+    index: u64,
+    // the queue of all tasks waiting on a number of permits, in arrival order
+    wait_queue: BTreeMap<u64, (u64, Option<Waker>)>,
+}
+
+impl SemQueue {
+    /// Called whenever permits become available (a guard was dropped) or a
+    /// new waiter joins the back of an already-contended queue: hand
+    /// `sem.state`'s permits to waiters at the front, in order. A request
+    /// that needs more permits than are available blocks the queue, so a
+    /// later, smaller request can never jump ahead of it. Clears `CONTENDED`
+    /// once the queue drains, letting the next `acquire` use the fast path.
+    fn drain(&mut self, sem: &AsyncSemaphore) {
+        while let Some((&index, &(needed, _))) = self.wait_queue.iter().next() {
+            let mut state = sem.state.load(Ordering::Acquire);
+            loop {
+                if needed > state & !CONTENDED {
+                    return;
+                }
+                match sem.state.compare_exchange_weak(
+                    state,
+                    state - needed,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => state = actual,
+                }
+            }
+
+            let (_, waker) = self.wait_queue.remove(&index).unwrap();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+
+        sem.clear_contended();
+    }
+}
+
+/// A counting semaphore: up to `n` permits may be held concurrently, with
+/// excess `acquire`s suspending until enough are released.
+pub struct AsyncSemaphore {
+    // the permit count in the low 63 bits, and the `CONTENDED` flag in the
+    // top bit, mutated through a CAS so an uncontended acquire/release never
+    // has to take `queue`'s lock at all.
+    state: AtomicU64,
+    queue: Mutex<SemQueue>,
+}
+
+impl AsyncSemaphore {
+    pub const fn new(permits: u64) -> Self {
+        Self {
+            state: AtomicU64::new(permits),
+            queue: Mutex::new(SemQueue {
+                index: 0,
+                wait_queue: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Try to take `count` permits with a single CAS loop, without touching
+    /// `queue` or marking the semaphore contended on failure. Used by the
+    /// non-blocking `try_acquire`, which shouldn't affect other callers just
+    /// because it didn't find enough permits available.
+    fn try_acquire_fast(&self, count: u64) -> bool {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            if state & CONTENDED != 0 || state & !CONTENDED < count {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state - count,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    /// Like `try_acquire_fast`, but if there aren't enough permits, marks the
+    /// semaphore contended in that same failed CAS rather than leaving a gap
+    /// between "we failed" and "we've taken the queue lock to enqueue" — a
+    /// gap a concurrent fast-path caller could otherwise slip a smaller
+    /// request through, jumping ahead of whoever arrived first.
+    fn try_acquire_fast_or_contend(&self, count: u64) -> bool {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            if state & CONTENDED != 0 {
+                return false;
+            }
+            if state & !CONTENDED < count {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state | CONTENDED,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return false,
+                    Err(actual) => state = actual,
+                }
+                continue;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state - count,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    /// Clear `CONTENDED` now that the queue is empty, via a CAS loop since a
+    /// plain store could otherwise clobber a concurrent `release`'s add to
+    /// the permit count.
+    fn clear_contended(&self) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            match self.state.compare_exchange_weak(
+                state,
+                state & !CONTENDED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    /// Give `count` permits back: straight into `state` if nobody's waiting,
+    /// or through the queue, to preserve FIFO order, otherwise.
+    fn release(&self, count: u64) {
+        let prev = self.state.fetch_add(count, Ordering::AcqRel);
+        if prev & CONTENDED != 0 {
+            self.queue.lock().unwrap().drain(self);
+        }
+    }
+
+    /// Try the fast path, and on failure register for `count` permits at the
+    /// back of the queue. Returns `None` if the fast path succeeded, or the
+    /// queue index to wait on otherwise.
+    fn acquire_or_enqueue(&self, count: u64) -> Option<u64> {
+        if self.try_acquire_fast_or_contend(count) {
+            return None;
+        }
+
+        // the failed fast-path attempt above already marked the semaphore
+        // contended (or found it already marked), so no concurrent fast-path
+        // call can take the permits we need while we queue up properly,
+        // which also re-checks `state` under the lock.
+        let mut queue = self.queue.lock().unwrap();
+        let index = queue.index;
+        queue.index += 1;
+        queue.wait_queue.insert(index, (count, None));
+        queue.drain(self);
+        Some(index)
+    }
+
+    pub fn acquire(&self, count: u64) -> AcquireN<'_> {
+        AcquireN {
+            sem: self,
+            index: self.acquire_or_enqueue(count),
+            count,
+            acquired: false,
+        }
+    }
+
+    /// Claim `count` permits only if they're immediately available, without
+    /// ever queuing or blocking.
+    pub fn try_acquire(&self, count: u64) -> Option<SemaphoreGuard<'_>> {
+        self.try_acquire_fast(count)
+            .then(|| SemaphoreGuard { sem: self, count })
+    }
+}
+
+pub struct SemaphoreGuard<'a> {
+    sem: &'a AsyncSemaphore,
+    count: u64,
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.sem.release(self.count);
+    }
+}
+
+pub struct AcquireN<'a> {
+    sem: &'a AsyncSemaphore,
+    // `None` means the fast path already reserved our permits at
+    // construction time, with no queue entry to check or remove.
+    index: Option<u64>,
+    count: u64,
+    acquired: bool,
+}
+
+impl<'a> Future for AcquireN<'a> {
+    type Output = SemaphoreGuard<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(index) = self.index else {
+            self.acquired = true;
+            return Poll::Ready(SemaphoreGuard {
+                sem: self.sem,
+                count: self.count,
+            });
+        };
+
+        let mut queue = self.sem.queue.lock().unwrap();
+
+        let Some((_, waker_slot)) = queue.wait_queue.get_mut(&index) else {
+            // if we were removed from the queue, that means we must hold the permits!
+            self.acquired = true;
+            return Poll::Ready(SemaphoreGuard {
+                sem: self.sem,
+                count: self.count,
+            });
+        };
+
+        *waker_slot = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl Drop for AcquireN<'_> {
+    fn drop(&mut self) {
+        if self.acquired {
+            return;
+        }
+
+        let Some(index) = self.index else {
+            // the fast path granted our permits, but we were dropped before
+            // ever being polled: give them back.
+            self.sem.release(self.count);
+            return;
+        };
+
+        let mut queue = self.sem.queue.lock().unwrap();
+        if queue.wait_queue.remove(&index).is_none() {
+            // we were already dequeued and handed the permits, but never
+            // turned that into a guard: release them exactly as a guard's
+            // Drop would.
+            self.sem.release(self.count);
+        } else if queue.wait_queue.is_empty() {
+            self.sem.clear_contended();
+        }
+    }
+}
+
+/// `AsyncMutex` is just an `AsyncSemaphore` with a single permit, guarding access to `T`.
+pub struct AsyncMutex<T> {
+    sem: AsyncSemaphore,
     data: UnsafeCell<T>,
 }
 
 unsafe impl<T: Send> Send for AsyncMutex<T> {}
 unsafe impl<T: Send> Sync for AsyncMutex<T> {}
 
+impl<T> AsyncMutex<T> {
+    pub const fn new(val: T) -> Self {
+        Self {
+            sem: AsyncSemaphore::new(1),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    pub fn lock(&self) -> Acquire<'_, T> {
+        Acquire {
+            inner: self.sem.acquire(1),
+            mutex: self,
+        }
+    }
+
+    /// Claim the lock only if it's immediately free, without ever queuing or blocking.
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        let permit = self.sem.try_acquire(1)?;
+        Some(AsyncMutexGuard {
+            mutex: self,
+            _permit: permit,
+        })
+    }
+
+    /// Like `lock`, but the returned guard owns an `Arc` to the mutex instead
+    /// of borrowing it, so it can be carried across a `tokio::spawn` boundary.
+    pub fn lock_arc(self: &Arc<Self>) -> AcquireArc<T> {
+        AcquireArc {
+            index: self.sem.acquire_or_enqueue(1),
+            mutex: Arc::clone(self),
+            acquired: false,
+        }
+    }
+}
+
 pub struct AsyncMutexGuard<'a, T> {
-    inner: &'a AsyncMutex<T>,
+    mutex: &'a AsyncMutex<T>,
+    // releases the one permit on drop; this guard needs no Drop impl of its own.
+    _permit: SemaphoreGuard<'a>,
 }
 
 impl<T> Deref for AsyncMutexGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        unsafe { &*self.inner.data.get() }
+        unsafe { &*self.mutex.data.get() }
     }
 }
 impl<T> DerefMut for AsyncMutexGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.inner.data.get() }
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+pub struct Acquire<'a, T> {
+    inner: AcquireN<'a>,
+    mutex: &'a AsyncMutex<T>,
+}
+
+impl<'a, T> Future for Acquire<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll(cx).map(|permit| AsyncMutexGuard {
+            mutex: this.mutex,
+            _permit: permit,
+        })
+    }
+}
+
+pub struct AsyncMutexGuardArc<T> {
+    mutex: Arc<AsyncMutex<T>>,
+}
+
+impl<T> Deref for AsyncMutexGuardArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+impl<T> DerefMut for AsyncMutexGuardArc<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.data.get() }
     }
 }
 
-impl<'a, T> Drop for AsyncMutexGuard<'a, T> {
+impl<T> Drop for AsyncMutexGuardArc<T> {
     fn drop(&mut self) {
-        let mut queue = self.inner.queue.lock().unwrap();
-        assert!(!queue.unlocked);
+        self.mutex.sem.release(1);
+    }
+}
 
-        // wake the next task in the queue
-        if let Some((_index, waker)) = queue.wait_queue.pop_first() {
-            if let Some(waker) = waker {
-                waker.wake();
-            }
-        } else {
-            // no one in the queue, leave in an unlocked state.
-            queue.unlocked = true;
+pub struct AcquireArc<T> {
+    mutex: Arc<AsyncMutex<T>>,
+    index: Option<u64>,
+    acquired: bool,
+}
+
+impl<T> Future for AcquireArc<T> {
+    type Output = AsyncMutexGuardArc<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(index) = self.index else {
+            self.acquired = true;
+            return Poll::Ready(AsyncMutexGuardArc {
+                mutex: Arc::clone(&self.mutex),
+            });
+        };
+
+        let mutex = Arc::clone(&self.mutex);
+        let mut queue = mutex.sem.queue.lock().unwrap();
+
+        let Some((_, waker_slot)) = queue.wait_queue.get_mut(&index) else {
+            drop(queue);
+            self.acquired = true;
+            return Poll::Ready(AsyncMutexGuardArc { mutex });
+        };
+
+        *waker_slot = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for AcquireArc<T> {
+    fn drop(&mut self) {
+        if self.acquired {
+            return;
+        }
+
+        let Some(index) = self.index else {
+            // the fast path granted our permit, but we were dropped before
+            // ever being polled: give it back.
+            self.mutex.sem.release(1);
+            return;
+        };
+
+        let mut queue = self.mutex.sem.queue.lock().unwrap();
+        if queue.wait_queue.remove(&index).is_none() {
+            self.mutex.sem.release(1);
+        } else if queue.wait_queue.is_empty() {
+            self.mutex.sem.clear_contended();
         }
     }
 }
 
-struct Queue {
+enum RwWaiter {
+    Read(Option<Waker>),
+    Write(Option<Waker>),
+    Upgradable(Option<Waker>),
+}
+
+struct RwQueue {
     // The current queue tail
     index: u64,
-    // the queue of all tasks waiting to acquire the mutex
-    wait_queue: BTreeMap<u64, Option<Waker>>,
+    // the queue of all tasks waiting to acquire the lock, in arrival order
+    wait_queue: BTreeMap<u64, RwWaiter>,
 
-    // the queue is currently unlocked.
-    unlocked: bool,
+    // how many readers currently hold the lock (the upgradable reader, if
+    // any, is included in this count)
+    readers: u64,
+    // whether a writer currently holds the lock
+    writer: bool,
+    // whether an upgradable reader currently holds the lock
+    upgradable: bool,
+    // whether the upgradable reader is waiting for the other readers to
+    // drain so it can become the writer; while this is set, new readers and
+    // upgradable readers queue instead of jumping in, so the upgrade can't
+    // be starved by a steady stream of new readers
+    upgrading: bool,
+    // the upgrading reader's waker, woken once `readers` drops to 1
+    upgrade_waker: Option<Waker>,
 }
 
-impl<T> AsyncMutex<T> {
+impl RwQueue {
+    /// Called whenever the lock becomes free (the last reader left, or the
+    /// writer left): hand it to the front of the queue, which is either a
+    /// single writer or a run of consecutive readers (including at most one
+    /// upgradable reader).
+    fn release(&mut self) {
+        let Some((&index, _)) = self.wait_queue.iter().next() else {
+            return;
+        };
+
+        match &self.wait_queue[&index] {
+            RwWaiter::Write(_) => {
+                let Some(RwWaiter::Write(waker)) = self.wait_queue.remove(&index) else {
+                    unreachable!()
+                };
+                self.writer = true;
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+            RwWaiter::Read(_) | RwWaiter::Upgradable(_) => {
+                while !self.upgrading {
+                    match self.wait_queue.iter().next() {
+                        Some((&index, RwWaiter::Read(_))) => {
+                            let Some(RwWaiter::Read(waker)) = self.wait_queue.remove(&index) else {
+                                unreachable!()
+                            };
+                            self.readers += 1;
+                            if let Some(waker) = waker {
+                                waker.wake();
+                            }
+                        }
+                        Some((&index, RwWaiter::Upgradable(_))) if !self.upgradable => {
+                            let Some(RwWaiter::Upgradable(waker)) = self.wait_queue.remove(&index) else {
+                                unreachable!()
+                            };
+                            self.readers += 1;
+                            self.upgradable = true;
+                            if let Some(waker) = waker {
+                                waker.wake();
+                            }
+                        }
+                        _ => break,
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct AsyncRwLock<T> {
+    queue: Mutex<RwQueue>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for AsyncRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for AsyncRwLock<T> {}
+
+impl<T> AsyncRwLock<T> {
     pub const fn new(val: T) -> Self {
         Self {
-            queue: Mutex::new(Queue {
+            queue: Mutex::new(RwQueue {
                 index: 0,
                 wait_queue: BTreeMap::new(),
-                unlocked: true,
+                readers: 0,
+                writer: false,
+                upgradable: false,
+                upgrading: false,
+                upgrade_waker: None,
             }),
             data: UnsafeCell::new(val),
         }
     }
 
-    pub fn lock(&self) -> Acquire<T> {
+    pub fn read(&self) -> ReadAcquire<'_, T> {
         let mut queue = self.queue.lock().unwrap();
         let index = queue.index;
         queue.index += 1;
 
-        if queue.unlocked {
-            // if the lock is currently unlocked, mark it as unlocked so we can claim it when polling.
-            assert!(queue.wait_queue.is_empty());
-            queue.unlocked = false;
+        // only jump the queue if no writer holds the lock and nobody is
+        // waiting (other readers already holding it are fine): if anyone else
+        // is already waiting (reader or writer) we queue behind them too, so
+        // a writer at the front is never starved by a stream of readers. An
+        // in-progress upgrade also blocks new readers from jumping in.
+        if !queue.writer && !queue.upgrading && queue.wait_queue.is_empty() {
+            queue.readers += 1;
         } else {
-            // register our interest to lock the mutex at the back of the queue
-            queue.wait_queue.insert(index, None);
+            queue.wait_queue.insert(index, RwWaiter::Read(None));
         }
 
-        Acquire {
-            mutex: self,
+        ReadAcquire {
+            lock: self,
+            index,
+            acquired: false,
+        }
+    }
+
+    /// Like `read`, but the returned guard can later be escalated to an
+    /// exclusive write guard with `upgrade`, without ever dropping the lock
+    /// in between. At most one upgradable reader may hold the lock at a time.
+    pub fn upgradable_read(&self) -> UpgradableReadAcquire<'_, T> {
+        let mut queue = self.queue.lock().unwrap();
+        let index = queue.index;
+        queue.index += 1;
+
+        if !queue.writer && !queue.upgradable && !queue.upgrading && queue.wait_queue.is_empty() {
+            queue.readers += 1;
+            queue.upgradable = true;
+        } else {
+            queue.wait_queue.insert(index, RwWaiter::Upgradable(None));
+        }
+
+        UpgradableReadAcquire {
+            lock: self,
+            index,
+            acquired: false,
+        }
+    }
+
+    pub fn write(&self) -> WriteAcquire<'_, T> {
+        let mut queue = self.queue.lock().unwrap();
+        let index = queue.index;
+        queue.index += 1;
+
+        if !queue.writer && queue.readers == 0 && queue.wait_queue.is_empty() {
+            queue.writer = true;
+        } else {
+            queue.wait_queue.insert(index, RwWaiter::Write(None));
+        }
+
+        WriteAcquire {
+            lock: self,
             index,
             acquired: false,
         }
     }
 }
 
-pub struct Acquire<'a, T> {
-    mutex: &'a AsyncMutex<T>,
+pub struct AsyncRwLockReadGuard<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+}
+
+impl<T> Deref for AsyncRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for AsyncRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut queue = self.lock.queue.lock().unwrap();
+        queue.readers -= 1;
+        if queue.readers == 0 {
+            queue.release();
+        } else if queue.readers == 1 {
+            // if an upgrade is waiting on the rest of the readers to drain,
+            // we might be the one it's waiting on.
+            if let Some(waker) = queue.upgrade_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct ReadAcquire<'a, T> {
+    lock: &'a AsyncRwLock<T>,
     index: u64,
     acquired: bool,
 }
 
-impl<'a, T> Future for Acquire<'a, T> {
-    type Output = AsyncMutexGuard<'a, T>;
+impl<'a, T> Future for ReadAcquire<'a, T> {
+    type Output = AsyncRwLockReadGuard<'a, T>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let index = self.index;
-        let mut queue = self.mutex.queue.lock().unwrap();
-        assert!(!queue.unlocked);
+        let mut queue = self.lock.queue.lock().unwrap();
 
-        let Some(waker_slot) = queue.wait_queue.get_mut(&index) else {
-            // if we were removed from the queue, that means we must be the next owner!
+        let Some(waiter) = queue.wait_queue.get_mut(&index) else {
             self.acquired = true;
-            return Poll::Ready(AsyncMutexGuard { inner: self.mutex });
+            return Poll::Ready(AsyncRwLockReadGuard { lock: self.lock });
         };
 
-        // we are still waiting in the queue.
+        let RwWaiter::Read(waker_slot) = waiter else {
+            unreachable!("a read request is never re-keyed as a write request")
+        };
         *waker_slot = Some(cx.waker().clone());
 
         Poll::Pending
     }
 }
 
-impl<'a, T> Drop for Acquire<'a, T> {
+impl<'a, T> Drop for ReadAcquire<'a, T> {
     fn drop(&mut self) {
-        // if we already acquired the lock, do nothing here.
         if self.acquired {
             return;
         }
 
+        let mut queue = self.lock.queue.lock().unwrap();
+        if queue.wait_queue.remove(&self.index).is_none() {
+            // we were already dequeued and handed the lock, but never turned
+            // that into a guard: release it exactly as a guard's Drop would.
+            queue.readers -= 1;
+            if queue.readers == 0 {
+                queue.release();
+            } else if queue.readers == 1 {
+                if let Some(waker) = queue.upgrade_waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+pub struct AsyncRwLockWriteGuard<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+}
+
+impl<T> Deref for AsyncRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for AsyncRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for AsyncRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut queue = self.lock.queue.lock().unwrap();
+        queue.writer = false;
+        queue.release();
+    }
+}
+
+pub struct WriteAcquire<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+    index: u64,
+    acquired: bool,
+}
+
+impl<'a, T> Future for WriteAcquire<'a, T> {
+    type Output = AsyncRwLockWriteGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let index = self.index;
-        let mut queue = self.mutex.queue.lock().unwrap();
+        let mut queue = self.lock.queue.lock().unwrap();
 
-        // we must remove ourselves from the wait queue if we are no longer waiting
-        if queue.wait_queue.remove(&index).is_none() {
-            // if we were removed from the queue already, that means we were about to be the next owner
-            // we should notify the next in the queue
+        let Some(waiter) = queue.wait_queue.get_mut(&index) else {
+            self.acquired = true;
+            return Poll::Ready(AsyncRwLockWriteGuard { lock: self.lock });
+        };
 
-            // wake the next task in the queue
-            if let Some((_index, waker)) = queue.wait_queue.pop_first() {
-                if let Some(waker) = waker {
-                    waker.wake();
+        let RwWaiter::Write(waker_slot) = waiter else {
+            unreachable!("a write request is never re-keyed as a read request")
+        };
+        *waker_slot = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for WriteAcquire<'a, T> {
+    fn drop(&mut self) {
+        if self.acquired {
+            return;
+        }
+
+        let mut queue = self.lock.queue.lock().unwrap();
+        if queue.wait_queue.remove(&self.index).is_none() {
+            // we were already dequeued and handed the lock, but never turned
+            // that into a guard: release it exactly as a guard's Drop would.
+            queue.writer = false;
+            queue.release();
+        }
+    }
+}
+
+pub struct AsyncRwLockUpgradableReadGuard<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+}
+
+impl<T> Deref for AsyncRwLockUpgradableReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for AsyncRwLockUpgradableReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut queue = self.lock.queue.lock().unwrap();
+        queue.upgradable = false;
+        queue.readers -= 1;
+        if queue.readers == 0 {
+            queue.release();
+        }
+    }
+}
+
+impl<'a, T> AsyncRwLockUpgradableReadGuard<'a, T> {
+    /// Escalate to an exclusive write guard, waiting for the other readers
+    /// (if any) to drain first. No writer can slip in ahead of us: while
+    /// this is pending, the queue blocks new readers and upgradable readers
+    /// from joining.
+    pub fn upgrade(self) -> UpgradeAcquire<'a, T> {
+        let lock = self.lock;
+        // suppress our own Drop: the reader slot we hold carries straight
+        // over into the write guard instead of being released and re-queued.
+        std::mem::forget(self);
+
+        lock.queue.lock().unwrap().upgrading = true;
+
+        UpgradeAcquire { lock, acquired: false }
+    }
+}
+
+pub struct UpgradableReadAcquire<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+    index: u64,
+    acquired: bool,
+}
+
+impl<'a, T> Future for UpgradableReadAcquire<'a, T> {
+    type Output = AsyncRwLockUpgradableReadGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let index = self.index;
+        let mut queue = self.lock.queue.lock().unwrap();
+
+        let Some(waiter) = queue.wait_queue.get_mut(&index) else {
+            self.acquired = true;
+            return Poll::Ready(AsyncRwLockUpgradableReadGuard { lock: self.lock });
+        };
+
+        let RwWaiter::Upgradable(waker_slot) = waiter else {
+            unreachable!("an upgradable-read request is never re-keyed as anything else")
+        };
+        *waker_slot = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for UpgradableReadAcquire<'a, T> {
+    fn drop(&mut self) {
+        if self.acquired {
+            return;
+        }
+
+        let mut queue = self.lock.queue.lock().unwrap();
+        if queue.wait_queue.remove(&self.index).is_none() {
+            // we were already dequeued and handed the lock, but never turned
+            // that into a guard: release it exactly as a guard's Drop would.
+            queue.upgradable = false;
+            queue.readers -= 1;
+            if queue.readers == 0 {
+                queue.release();
+            }
+        }
+    }
+}
+
+pub struct UpgradeAcquire<'a, T> {
+    lock: &'a AsyncRwLock<T>,
+    acquired: bool,
+}
+
+impl<'a, T> Future for UpgradeAcquire<'a, T> {
+    type Output = AsyncRwLockWriteGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut queue = self.lock.queue.lock().unwrap();
+
+        if queue.readers == 1 {
+            queue.readers = 0;
+            queue.writer = true;
+            queue.upgradable = false;
+            queue.upgrading = false;
+            self.acquired = true;
+            return Poll::Ready(AsyncRwLockWriteGuard { lock: self.lock });
+        }
+
+        queue.upgrade_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for UpgradeAcquire<'a, T> {
+    fn drop(&mut self) {
+        if self.acquired {
+            return;
+        }
+
+        // dropped while still waiting for the other readers to drain: we
+        // never released our reader slot, so give it back as a plain read,
+        // rather than losing the permit entirely.
+        let mut queue = self.lock.queue.lock().unwrap();
+        queue.upgrading = false;
+        queue.upgradable = false;
+        queue.readers -= 1;
+        if queue.readers == 0 {
+            queue.release();
+        }
+    }
+}
+
+const BI_UNLOCKED: usize = 0;
+const BI_LOCKED: usize = 1;
+const BI_LOCKED_PARKED: usize = 2;
+
+struct BiInner<T> {
+    state: AtomicUsize,
+    // guarded by `state`: only whichever half is currently unable to lock
+    // may store its waker here, and only the half that unlocks may take it.
+    waker: UnsafeCell<Option<Waker>>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for BiInner<T> {}
+unsafe impl<T: Send> Sync for BiInner<T> {}
+
+/// A mutex specialized to exactly two owners, such as the read and write
+/// halves of a split duplex connection. Cheaper than `AsyncMutex`: with at
+/// most one other contender, a single `AtomicUsize` and a single stored
+/// `Waker` replace the FIFO queue entirely.
+pub struct BiLock<T> {
+    inner: Arc<BiInner<T>>,
+}
+
+impl<T> BiLock<T> {
+    /// Wrap `value` and split it into its two owning halves.
+    pub fn new(value: T) -> (Self, Self) {
+        let inner = Arc::new(BiInner {
+            state: AtomicUsize::new(BI_UNLOCKED),
+            waker: UnsafeCell::new(None),
+            value: UnsafeCell::new(value),
+        });
+
+        (
+            BiLock {
+                inner: Arc::clone(&inner),
+            },
+            BiLock { inner },
+        )
+    }
+
+    pub fn lock(&self) -> BiAcquire<'_, T> {
+        BiAcquire { lock: self }
+    }
+
+    fn poll_lock(&self, cx: &mut Context<'_>) -> Poll<BiLockGuard<'_, T>> {
+        match self.inner.state.swap(BI_LOCKED, Ordering::Acquire) {
+            BI_UNLOCKED => Poll::Ready(BiLockGuard { lock: self }),
+            BI_LOCKED => {
+                // the other half holds the lock: park our waker and tell it
+                // to wake us, unless it unlocked in the meantime.
+                // SAFETY: the other half can't also be storing a waker right
+                // now, since it's the one holding BI_LOCKED.
+                unsafe {
+                    *self.inner.waker.get() = Some(cx.waker().clone());
+                }
+
+                match self.inner.state.compare_exchange(
+                    BI_LOCKED,
+                    BI_LOCKED_PARKED,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => Poll::Pending,
+                    Err(BI_UNLOCKED) => self.poll_lock(cx),
+                    Err(_) => unreachable!("with only two halves, at most one waiter is ever parked"),
                 }
-            } else {
-                // no one in the queue, leave in an unlocked state.
-                queue.unlocked = true;
             }
-        };
+            BI_LOCKED_PARKED => {
+                unreachable!("a half never polls its own lock future concurrently from two places")
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn unlock(&self) {
+        match self.inner.state.swap(BI_UNLOCKED, Ordering::Release) {
+            BI_LOCKED => {}
+            BI_LOCKED_PARKED => {
+                // SAFETY: we're the one releasing the lock, so any parked
+                // waker belongs to the other half and is ours to take.
+                let waker = unsafe { (*self.inner.waker.get()).take() };
+                waker
+                    .expect("BI_LOCKED_PARKED implies a waker was stored")
+                    .wake();
+            }
+            _ => unreachable!("can't unlock a BiLock that isn't held"),
+        }
+    }
+
+    /// Recombine the two halves of a `BiLock::new` pair back into the value
+    /// they share, provided `self` and `other` really are that pair.
+    pub fn reunite(self, other: Self) -> Result<T, ReuniteError<T>> {
+        if Arc::ptr_eq(&self.inner, &other.inner) {
+            drop(other);
+            // SAFETY: dropping `other` above leaves `self.inner` as the only
+            // remaining reference.
+            let inner = Arc::try_unwrap(self.inner).unwrap_or_else(|_| unreachable!());
+            Ok(inner.value.into_inner())
+        } else {
+            Err(ReuniteError(self, other))
+        }
+    }
+}
+
+/// Returned by `BiLock::reunite` when the two halves don't come from the same `BiLock::new` pair.
+pub struct ReuniteError<T>(pub BiLock<T>, pub BiLock<T>);
+
+impl<T> std::fmt::Debug for ReuniteError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ReuniteError").finish()
+    }
+}
+
+pub struct BiLockGuard<'a, T> {
+    lock: &'a BiLock<T>,
+}
+
+impl<T> Deref for BiLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.inner.value.get() }
+    }
+}
+
+impl<T> DerefMut for BiLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.inner.value.get() }
+    }
+}
+
+impl<T> Drop for BiLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+pub struct BiAcquire<'a, T> {
+    lock: &'a BiLock<T>,
+}
+
+impl<'a, T> Future for BiAcquire<'a, T> {
+    type Output = BiLockGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.lock.poll_lock(cx)
     }
 }
 
@@ -183,4 +1056,92 @@ async fn main() {
     println!("task 0 acquired the lock");
 
     println!("Shutting down with value {val}");
+
+    // try_lock never blocks: it succeeds while the mutex is free...
+    match mutex.try_lock() {
+        Some(lock) => println!("try_lock succeeded with value {}", *lock),
+        None => println!("try_lock found the mutex held"),
+    }
+
+    // ...and fails outright, rather than queuing, while it's held.
+    let held = mutex.lock_arc().await;
+    match mutex.try_lock() {
+        Some(_) => println!("try_lock unexpectedly succeeded"),
+        None => println!("try_lock found the mutex held"),
+    }
+    drop(held);
+
+    // lock_arc's guard owns its Arc, so it can cross a spawn boundary.
+    let mutex3 = mutex.clone();
+    tokio::spawn(async move {
+        let mut lock = mutex3.lock_arc().await;
+        *lock += 1;
+        println!("spawned task holding an owned guard set value to {}", *lock);
+    })
+    .await
+    .unwrap();
+
+    let rwlock = Arc::new(AsyncRwLock::new(0));
+
+    let mut readers = Vec::new();
+    for i in 0..3 {
+        let rwlock = rwlock.clone();
+        readers.push(tokio::spawn(async move {
+            let val = rwlock.read().await;
+            println!("reader {i} sees {}", *val);
+        }));
+    }
+    for reader in readers {
+        reader.await.unwrap();
+    }
+
+    let mut writer = rwlock.write().await;
+    *writer += 1;
+    println!("writer set value to {}", *writer);
+    drop(writer);
+
+    // an upgradable reader can escalate straight to a writer without ever
+    // releasing the lock in between, so no other writer can sneak in.
+    let upgradable = rwlock.upgradable_read().await;
+    println!("upgradable reader sees {}", *upgradable);
+    let mut upgraded = upgradable.upgrade().await;
+    *upgraded += 1;
+    println!("upgraded to a writer and set value to {}", *upgraded);
+    drop(upgraded);
+
+    // rate-limit five tasks down to two running at a time.
+    let sem = Arc::new(AsyncSemaphore::new(2));
+
+    let mut jobs = Vec::new();
+    for i in 0..5 {
+        let sem = sem.clone();
+        jobs.push(tokio::spawn(async move {
+            let _permit = sem.acquire(1).await;
+            println!("job {i} running");
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            println!("job {i} done");
+        }));
+    }
+    for job in jobs {
+        job.await.unwrap();
+    }
+
+    // split a buffer into a reader half and a writer half, handing each to
+    // its own task, then reunite them once both are done.
+    let (reader, writer) = BiLock::new(Vec::new());
+
+    let writer_task = tokio::spawn(async move {
+        writer.lock().await.push(1);
+        writer
+    });
+    let reader_task = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        reader.lock().await.push(2);
+        reader
+    });
+
+    let writer = writer_task.await.unwrap();
+    let reader = reader_task.await.unwrap();
+    let buf = writer.reunite(reader).expect("both halves came from the same BiLock::new");
+    println!("reunited buffer: {buf:?}");
 }
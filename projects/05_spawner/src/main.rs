@@ -3,33 +3,41 @@ use std::{
     collections::VecDeque,
     future::Future,
     pin::Pin,
-    sync::{Arc, Condvar, Mutex, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
     task::{Context, Poll, Wake, Waker},
 };
 
+/// How many tasks an idle worker grabs from the injector at once, so it
+/// doesn't have to go back to the shared lock for every single task.
+const STEAL_BATCH: usize = 32;
+
 struct Task {
     fut: Mutex<Pin<Box<dyn Future<Output = ()> + Send + 'static>>>,
+    /// set by `AbortHandle::abort` to request cancellation before the next poll.
+    aborted: Arc<AtomicBool>,
 }
 
 struct Runtime {
     park: Condvar,
-    worker: Mutex<Worker>,
+    state: Mutex<RuntimeState>,
+    /// one queue per worker thread, stolen from by idle siblings.
+    workers: Vec<WorkerQueue>,
 }
 
-struct Worker {
-    /// whether the root task is ready
-    root_task: bool,
-    /// all the spawned tasks that are ready
-    tasks: VecDeque<Arc<Task>>,
-    /// the state the worker is currently in
-    state: WorkerState,
+struct RuntimeState {
+    /// tasks that are ready to run but haven't yet landed in a worker's local queue.
+    injector: VecDeque<Arc<Task>>,
+    /// whether the root future (on the calling thread) has been woken.
+    root_ready: bool,
+    /// set once the root future has completed, so workers know to exit.
+    shutdown: bool,
 }
 
-#[derive(PartialEq)]
-enum WorkerState {
-    Running,
-    Parked,
-    Ready,
+struct WorkerQueue {
+    local: Mutex<VecDeque<Arc<Task>>>,
 }
 
 struct SimpleWaker {
@@ -46,20 +54,18 @@ impl Wake for SimpleWaker {
             return;
         };
 
-        let mut worker = runtime.worker.lock().unwrap();
+        let mut state = runtime.state.lock().unwrap();
 
-        if let Some(task) = &self.task {
-            worker.tasks.push_back(task.clone());
-        } else {
-            worker.root_task = true;
+        match &self.task {
+            Some(task) => state.injector.push_back(Arc::clone(task)),
+            None => state.root_ready = true,
         }
 
-        // if the worker thread is parked, tell it to wake up.
-        if worker.state == WorkerState::Parked {
-            runtime.park.notify_one();
-        }
+        drop(state);
 
-        worker.state = WorkerState::Ready
+        // the calling thread and every worker share this condvar, so wake them
+        // all and let each re-check whether there's anything for it to do.
+        runtime.park.notify_all();
     }
 }
 
@@ -67,20 +73,34 @@ thread_local! {
     static RUNTIME: RefCell<Option<Arc<Runtime>>> = const { RefCell::new(None) };
 }
 
-pub fn block_on<F: Future>(f: F) -> F::Output {
+/// Runs `f` to completion on the calling thread, backed by a pool of `n`
+/// work-stealing worker threads that run any tasks it spawns.
+pub fn block_on_with_threads<F: Future>(n: usize, f: F) -> F::Output {
     let mut f = std::pin::pin!(f);
 
     let runtime = Arc::new(Runtime {
         park: Condvar::new(),
-        worker: Mutex::new(Worker {
-            root_task: false,
-            tasks: VecDeque::new(),
-            state: WorkerState::Running,
+        state: Mutex::new(RuntimeState {
+            injector: VecDeque::new(),
+            root_ready: false,
+            shutdown: false,
         }),
+        workers: (0..n)
+            .map(|_| WorkerQueue {
+                local: Mutex::new(VecDeque::new()),
+            })
+            .collect(),
     });
 
     let prev = RUNTIME.replace(Some(Arc::clone(&runtime)));
 
+    let handles: Vec<_> = (0..n)
+        .map(|idx| {
+            let runtime = Arc::clone(&runtime);
+            std::thread::spawn(move || worker_main(idx, runtime))
+        })
+        .collect();
+
     let root_waker_state = Arc::new(SimpleWaker {
         runtime: Arc::downgrade(&runtime),
         task: None,
@@ -92,74 +112,334 @@ pub fn block_on<F: Future>(f: F) -> F::Output {
         match f.as_mut().poll(&mut Context::from_waker(&root_waker)) {
             Poll::Ready(output) => break output,
             Poll::Pending => {
-                let mut worker = runtime.worker.lock().unwrap();
+                let mut state = runtime.state.lock().unwrap();
+                while !state.root_ready {
+                    state = runtime.park.wait(state).unwrap();
+                }
+                state.root_ready = false;
+            }
+        }
+    };
 
-                while let Some(task) = worker.tasks.pop_front() {
-                    drop(worker);
+    runtime.state.lock().unwrap().shutdown = true;
+    runtime.park.notify_all();
+    for handle in handles {
+        handle.join().unwrap();
+    }
 
-                    let task_waker_state = Arc::new(SimpleWaker {
-                        runtime: Arc::downgrade(&runtime),
-                        task: Some(task.clone()),
-                    });
+    RUNTIME.set(prev);
 
-                    let task_waker = Waker::from(task_waker_state);
+    res
+}
 
-                    let mut f = task.fut.lock().unwrap();
-                    _ = f.as_mut().poll(&mut Context::from_waker(&task_waker));
-                    drop(f);
+/// Runs `f` to completion, using one worker thread per available core.
+pub fn block_on<F: Future>(f: F) -> F::Output {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    block_on_with_threads(threads, f)
+}
 
-                    worker = runtime.worker.lock().unwrap();
-                }
+fn worker_main(idx: usize, runtime: Arc<Runtime>) {
+    let prev = RUNTIME.replace(Some(Arc::clone(&runtime)));
 
-                // park until we are notified to be ready
-                while worker.state != WorkerState::Ready {
-                    worker.state = WorkerState::Parked;
-                    worker = runtime.park.wait(worker).unwrap();
-                }
+    loop {
+        if let Some(task) = next_task(idx, &runtime) {
+            poll_task(&runtime, task);
+            continue;
+        }
+
+        let mut state = runtime.state.lock().unwrap();
+        if state.shutdown {
+            break;
+        }
+
+        while state.injector.is_empty() && !state.shutdown && !has_stealable_work(idx, &runtime) {
+            state = runtime.park.wait(state).unwrap();
+        }
+    }
 
-                // announce that we are running the task and are not idle.
-                worker.state = WorkerState::Running;
+    RUNTIME.set(prev);
+}
+
+/// Local queue first, then a batch from the injector, then stealing from a sibling.
+fn next_task(idx: usize, runtime: &Arc<Runtime>) -> Option<Arc<Task>> {
+    if let Some(task) = runtime.workers[idx].local.lock().unwrap().pop_front() {
+        return Some(task);
+    }
+
+    let mut state = runtime.state.lock().unwrap();
+    if let Some(task) = state.injector.pop_front() {
+        let mut local = runtime.workers[idx].local.lock().unwrap();
+        while local.len() < STEAL_BATCH {
+            match state.injector.pop_front() {
+                Some(extra) => local.push_back(extra),
+                None => break,
             }
         }
+        drop(local);
+        drop(state);
+        return Some(task);
+    }
+    drop(state);
+
+    steal(idx, runtime)
+}
+
+fn has_stealable_work(idx: usize, runtime: &Arc<Runtime>) -> bool {
+    let n = runtime.workers.len();
+    (1..n).any(|offset| {
+        let sibling = (idx + offset) % n;
+        sibling != idx && runtime.workers[sibling].local.lock().unwrap().len() > 1
+    })
+}
+
+/// Steal half of a sibling worker's local queue, round-robin starting from `idx + 1`.
+fn steal(idx: usize, runtime: &Arc<Runtime>) -> Option<Arc<Task>> {
+    let n = runtime.workers.len();
+    for offset in 1..n {
+        let sibling = (idx + offset) % n;
+
+        let mut sib_local = runtime.workers[sibling].local.lock().unwrap();
+        let len = sib_local.len();
+        if len <= 1 {
+            continue;
+        }
+
+        let mut stolen = sib_local.split_off(len / 2);
+        drop(sib_local);
+
+        let task = stolen.pop_front();
+        if !stolen.is_empty() {
+            runtime.workers[idx].local.lock().unwrap().extend(stolen);
+        }
+        return task;
+    }
+    None
+}
+
+fn poll_task(runtime: &Arc<Runtime>, task: Arc<Task>) {
+    // a task must only ever be polled by one worker at a time: if another
+    // worker already holds the lock (e.g. a duplicate wakeup raced us here)
+    // we simply skip it and let that worker finish the poll. an abort is
+    // different: if we lost the lock to an in-flight ordinary poll, nothing
+    // else will notice the abort on its behalf, so re-enqueue and retry once
+    // that poll releases the lock.
+    let Ok(mut f) = task.fut.try_lock() else {
+        if task.aborted.load(Ordering::Acquire) {
+            runtime.state.lock().unwrap().injector.push_back(Arc::clone(&task));
+            runtime.park.notify_all();
+        }
+        return;
     };
 
-    RUNTIME.set(prev);
+    // only decide abort-vs-poll once we actually hold the lock, so whichever
+    // worker ends up holding it is the one that makes the call; checking
+    // `aborted` before taking the lock left a window where a poll already in
+    // flight would run to completion without ever seeing the abort.
+    if task.aborted.load(Ordering::Acquire) {
+        *f = Box::pin(std::future::pending());
+        return;
+    }
 
-    res
+    let waker = Waker::from(Arc::new(SimpleWaker {
+        runtime: Arc::downgrade(runtime),
+        task: Some(Arc::clone(&task)),
+    }));
+
+    _ = f.as_mut().poll(&mut Context::from_waker(&waker));
+}
+
+/// Why a joined task did not produce a value.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The task was dropped without ever completing.
+    Cancelled,
+    /// The task was aborted via `JoinHandle::abort` or `AbortHandle::abort`.
+    Aborted,
 }
 
-pub fn spawn<F: Future<Output = ()> + Send + 'static>(f: F) {
-    RUNTIME.with_borrow(|rt| {
-        let runtime = rt.as_ref().expect("runtime should be set");
-        let mut worker = runtime.worker.lock().unwrap();
+/// The eventual outcome of a spawned task: its output, or why it never
+/// produced one.
+enum JoinState<T> {
+    Pending,
+    Done(T),
+    Cancelled,
+    Aborted,
+}
+
+struct JoinInner<T> {
+    state: Mutex<JoinState<T>>,
+    /// The waker of whoever is awaiting the `JoinHandle`, if any.
+    waker: Mutex<Option<Waker>>,
+}
 
-        worker.tasks.push_back(Arc::new(Task {
-            fut: Mutex::new(Box::pin(f)),
-        }));
+pin_project_lite::pin_project! {
+    /// Wraps a spawned future so its output (or cancellation) is reported
+    /// through a `JoinHandle` instead of being discarded.
+    struct JoinFuture<F: Future> {
+        #[pin]
+        fut: F,
+        inner: Arc<JoinInner<F::Output>>,
+        aborted: Arc<AtomicBool>,
+    }
 
-        // if the worker thread is parked, tell it to wake up.
-        if worker.state == WorkerState::Parked {
-            runtime.park.notify_one();
+    impl<F: Future> PinnedDrop for JoinFuture<F> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            let mut state = this.inner.state.lock().unwrap();
+            if matches!(*state, JoinState::Pending) {
+                *state = if this.aborted.load(Ordering::Acquire) {
+                    JoinState::Aborted
+                } else {
+                    JoinState::Cancelled
+                };
+                drop(state);
+                if let Some(waker) = this.inner.waker.lock().unwrap().take() {
+                    waker.wake();
+                }
+            }
         }
+    }
+}
+
+impl<F: Future> Future for JoinFuture<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.project();
 
-        worker.state = WorkerState::Ready
+        let Poll::Ready(output) = this.fut.poll(cx) else {
+            return Poll::Pending;
+        };
+
+        *this.inner.state.lock().unwrap() = JoinState::Done(output);
+        if let Some(waker) = this.inner.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+
+        Poll::Ready(())
+    }
+}
+
+/// A handle that can cancel a spawned task without being able to join it.
+///
+/// Obtained from `JoinHandle::abort_handle`, or by cloning another `AbortHandle`.
+#[derive(Clone)]
+pub struct AbortHandle {
+    aborted: Arc<AtomicBool>,
+    task: Weak<Task>,
+    runtime: Weak<Runtime>,
+}
+
+impl AbortHandle {
+    /// Request that the task stop running. It will not be polled again; if it
+    /// has not already completed, its `JoinHandle` resolves to `Err(JoinError::Aborted)`.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Release);
+
+        let (Some(task), Some(runtime)) = (self.task.upgrade(), self.runtime.upgrade()) else {
+            // the task or runtime has already gone away.
+            return;
+        };
+
+        runtime.state.lock().unwrap().injector.push_back(task);
+        runtime.park.notify_all();
+    }
+}
+
+/// A handle to a spawned task, awaitable for its eventual output.
+///
+/// Dropping the handle does not stop the task: it keeps running detached.
+pub struct JoinHandle<T> {
+    inner: Arc<JoinInner<T>>,
+    abort: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    /// Request that the task be cancelled. See `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// Obtain a cloneable handle that can abort the task without joining it.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.inner.state.lock().unwrap();
+        match std::mem::replace(&mut *state, JoinState::Pending) {
+            JoinState::Done(v) => Poll::Ready(Ok(v)),
+            JoinState::Cancelled => Poll::Ready(Err(JoinError::Cancelled)),
+            JoinState::Aborted => Poll::Ready(Err(JoinError::Aborted)),
+            JoinState::Pending => {
+                drop(state);
+                *self.inner.waker.lock().unwrap() = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub fn spawn<F>(f: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let inner = Arc::new(JoinInner {
+        state: Mutex::new(JoinState::Pending),
+        waker: Mutex::new(None),
+    });
+    let aborted = Arc::new(AtomicBool::new(false));
+
+    let task = Arc::new(Task {
+        fut: Mutex::new(Box::pin(JoinFuture {
+            fut: f,
+            inner: Arc::clone(&inner),
+            aborted: Arc::clone(&aborted),
+        })),
+        aborted,
     });
+
+    let runtime = RUNTIME.with_borrow(|rt| Arc::clone(rt.as_ref().expect("runtime should be set")));
+
+    runtime.state.lock().unwrap().injector.push_back(Arc::clone(&task));
+    runtime.park.notify_all();
+
+    let abort = AbortHandle {
+        aborted: Arc::clone(&task.aborted),
+        task: Arc::downgrade(&task),
+        runtime: Arc::downgrade(&runtime),
+    };
+
+    JoinHandle { inner, abort }
 }
 
 fn main() {
-    block_on(async move {
+    block_on_with_threads(4, async move {
         let (watch_tx, watch_rx) = tokio::sync::watch::channel(true);
 
+        let mut handles = Vec::new();
         for i in 0..10 {
             let mut watch_rx = watch_rx.clone();
-            spawn(async move {
+            handles.push(spawn(async move {
                 // wait until we are no longer running
                 watch_rx.wait_for(|running| !*running).await.unwrap();
                 // bad_sleep(start + std::time::Duration::from_secs(1)).await;
-                println!("completed {i}")
-            });
+                println!("completed {i}");
+                i
+            }));
         }
 
+        // this one never gets a chance to observe the watch channel flip:
+        // we abort it almost immediately to show cancellation working.
+        let aborted = spawn(std::future::pending::<()>());
+        aborted.abort();
+        println!("abort result: {:?}", aborted.await);
+
         let (tx, rx) = tokio::sync::oneshot::channel();
         std::thread::spawn(move || {
             std::thread::sleep(std::time::Duration::from_secs(2));
@@ -169,6 +449,11 @@ fn main() {
         });
 
         rx.await.unwrap();
+
+        for handle in handles {
+            let i = handle.await.expect("task should not be cancelled");
+            println!("joined {i}");
+        }
     });
 
     println!("done");
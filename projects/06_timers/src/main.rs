@@ -245,6 +245,101 @@ pub async fn sleep_until(deadline: Instant) -> Instant {
     .await
 }
 
+/// A heap-allocated, dynamically-dispatched future, boxed up so a collection
+/// can hold a mix of concrete future types.
+type BoxFuture<O> = Pin<Box<dyn Future<Output = O>>>;
+
+/// Polls a set of futures together, returning the output of whichever
+/// completes first along with its index and the rest, untouched.
+///
+/// A rotating start offset means no single future is favoured: the one after
+/// whichever index we started at last time gets polled first.
+struct SelectAll<O> {
+    futures: Vec<BoxFuture<O>>,
+    offset: usize,
+}
+
+impl<O> Future for SelectAll<O> {
+    type Output = (O, usize, Vec<BoxFuture<O>>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let n = this.futures.len();
+        let start = this.offset % n;
+        this.offset = this.offset.wrapping_add(1);
+
+        for i in 0..n {
+            let idx = (start + i) % n;
+            if let Poll::Ready(output) = this.futures[idx].as_mut().poll(cx) {
+                let mut remaining = std::mem::take(&mut this.futures);
+                drop(remaining.remove(idx));
+                return Poll::Ready((output, idx, remaining));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Race a list of futures, returning the output of the first to complete,
+/// its index in `futures`, and the other futures for the caller to reuse.
+fn select_all<O>(futures: Vec<BoxFuture<O>>) -> SelectAll<O> {
+    assert!(!futures.is_empty(), "select_all requires at least one future");
+    SelectAll { futures, offset: 0 }
+}
+
+enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// Returned by `timeout` when `dur` elapses before the wrapped future completes.
+#[derive(Debug)]
+pub struct Elapsed;
+
+/// Run `fut`, but give up with `Err(Elapsed)` if it hasn't finished by `dur`.
+pub async fn timeout<F: Future + 'static>(dur: Duration, fut: F) -> Result<F::Output, Elapsed> {
+    let fut: BoxFuture<Either<F::Output, Instant>> = Box::pin(async move { Either::Left(fut.await) });
+    let sleep: BoxFuture<Either<F::Output, Instant>> =
+        Box::pin(async move { Either::Right(sleep_until(Instant::now() + dur).await) });
+
+    match select_all(vec![fut, sleep]).await.0 {
+        Either::Left(output) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+/// A periodic timer built on `sleep_until`.
+pub struct Interval {
+    period: Duration,
+    next: Instant,
+}
+
+/// Creates an `Interval` that first fires after `period`, then every `period` after that.
+pub fn interval(period: Duration) -> Interval {
+    Interval {
+        period,
+        next: Instant::now() + period,
+    }
+}
+
+impl Interval {
+    /// Wait for the next tick, returning the instant we actually woke up.
+    pub async fn tick(&mut self) -> Instant {
+        let now = sleep_until(self.next).await;
+        self.next += self.period;
+
+        // if we're so far behind that the *next* tick has also already
+        // elapsed, skip forward instead of firing every missed tick back to
+        // back (burst-then-skip).
+        while self.next <= now {
+            self.next += self.period;
+        }
+
+        now
+    }
+}
+
 fn main() {
     let start = std::time::Instant::now();
     let deadline = start + std::time::Duration::from_secs(1);
@@ -253,4 +348,30 @@ fn main() {
     let lag = woken - deadline;
 
     println!("{lag:?}");
+
+    block_on(async {
+        let fast = async {
+            sleep_until(Instant::now() + Duration::from_millis(100)).await;
+            "done"
+        };
+        match timeout(Duration::from_millis(500), fast).await {
+            Ok(value) => println!("completed before timeout: {value}"),
+            Err(Elapsed) => println!("timed out"),
+        }
+
+        let slow = async {
+            sleep_until(Instant::now() + Duration::from_secs(2)).await;
+            "done"
+        };
+        match timeout(Duration::from_millis(100), slow).await {
+            Ok(value) => println!("completed before timeout: {value}"),
+            Err(Elapsed) => println!("timed out"),
+        }
+
+        let mut ticks = interval(Duration::from_millis(200));
+        for _ in 0..3 {
+            ticks.tick().await;
+            println!("tick at {:?}", Instant::now());
+        }
+    });
 }